@@ -1,15 +1,21 @@
-use flate2::{write::GzEncoder, Compression};
+use flate2::{
+    write::{DeflateEncoder, GzEncoder},
+    Compression,
+};
 use std::{
     collections::HashMap,
     env::{self, set_current_dir},
     fmt::{self, Debug},
-    fs::{read, write},
-    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Result, Write},
+    fs::{metadata, read, write},
+    io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write},
     net::{TcpListener, TcpStream},
     path::Path,
     thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+const DEFAULT_KEEP_ALIVE_TIMEOUT_SECS: u64 = 5;
+
 #[derive(Debug, PartialEq)]
 enum Method {
     Get,
@@ -26,8 +32,13 @@ impl fmt::Display for Method {
 #[derive(Debug)]
 enum Status {
     Ok,
+    PartialContent,
     Created,
+    BadRequest,
+    RequestTimeout,
+    NotModified,
     NotFound,
+    RangeNotSatisfiable,
     ServerError,
 }
 
@@ -41,24 +52,150 @@ impl Status {
     fn as_str(&self) -> &'static str {
         match self {
             Status::Ok => "200 OK",
+            Status::PartialContent => "206 Partial Content",
             Status::Created => "201 Created",
+            Status::BadRequest => "400 Bad Request",
+            Status::RequestTimeout => "408 Request Timeout",
+            Status::NotModified => "304 Not Modified",
             Status::NotFound => "404 Not Found",
+            Status::RangeNotSatisfiable => "416 Range Not Satisfiable",
             Status::ServerError => "500 Internal Server Error",
         }
     }
 }
 
+enum Range {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+fn parse_range(value: &str, total: u64) -> Range {
+    let spec = match value.strip_prefix("bytes=") {
+        Some(spec) => spec,
+        None => return Range::Full,
+    };
+
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return Range::Full,
+    };
+
+    if total == 0 {
+        return Range::Unsatisfiable;
+    }
+
+    if start.is_empty() {
+        let suffix = match end.parse::<u64>() {
+            Ok(suffix) => suffix,
+            Err(_) => return Range::Full,
+        };
+
+        if suffix == 0 {
+            return Range::Unsatisfiable;
+        }
+
+        return Range::Partial(total.saturating_sub(suffix), total - 1);
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return Range::Full,
+    };
+
+    if start >= total {
+        return Range::Unsatisfiable;
+    }
+
+    let end = if end.is_empty() {
+        total - 1
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) => end.min(total - 1),
+            Err(_) => return Range::Full,
+        }
+    };
+
+    if end < start {
+        return Range::Unsatisfiable;
+    }
+
+    return Range::Partial(start, end);
+}
+
+fn to_unix_seconds(time: SystemTime) -> u64 {
+    return time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+}
+
+fn etag(total: u64, modified: SystemTime) -> String {
+    return format!("W/\"{:x}-{:x}\"", total, to_unix_seconds(modified));
+}
+
+fn is_not_modified(request: &Request, modified: SystemTime, etag: &str) -> bool {
+    if let Some(if_none_match) = request.headers.get("If-None-Match") {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag == etag);
+    }
+
+    if let Some(if_modified_since) = request.headers.get("If-Modified-Since") {
+        if let Ok(since) = httpdate::parse_http_date(if_modified_since) {
+            return to_unix_seconds(modified) <= to_unix_seconds(since);
+        }
+    }
+
+    return false;
+}
+
+#[derive(Default)]
+struct Headers {
+    entries: HashMap<String, (String, String)>,
+}
+
+impl Headers {
+    fn new() -> Headers {
+        Headers {
+            entries: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        let key = key.into();
+        self.entries.insert(key.to_lowercase(), (key, value.into()));
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .get(&key.to_lowercase())
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl IntoIterator for Headers {
+    type Item = (String, String);
+    type IntoIter = std::collections::hash_map::IntoValues<String, (String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_values()
+    }
+}
+
 struct Request {
     pub method: Method,
     pub path: String,
     pub version: String,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Option<Vec<u8>>,
+    pub expect_continue: bool,
 }
 
 struct Response {
     pub status: Status,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Option<Vec<u8>>,
 }
 
@@ -66,14 +203,14 @@ impl Response {
     pub fn status(status: Status) -> Response {
         Response {
             status,
-            headers: HashMap::new(),
+            headers: Headers::new(),
             body: None,
         }
     }
 
     pub fn text(status: Status, text: String) -> Response {
-        let mut headers: HashMap<String, String> = HashMap::new();
-        headers.insert("Content-Type".into(), "text/plain".into());
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "text/plain");
 
         Response {
             status,
@@ -83,8 +220,8 @@ impl Response {
     }
 
     pub fn binary(data: Vec<u8>) -> Response {
-        let mut headers: HashMap<String, String> = HashMap::new();
-        headers.insert("Content-Type".into(), "application/octet-stream".into());
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "application/octet-stream");
 
         Response {
             status: Status::Ok,
@@ -92,16 +229,50 @@ impl Response {
             body: Some(data),
         }
     }
+
+    pub fn with_body(status: Status, content_type: &str, body: Vec<u8>) -> Response {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", content_type);
+
+        Response {
+            status,
+            headers,
+            body: Some(body),
+        }
+    }
+}
+
+enum ReadOutcome {
+    Request(Request),
+    Closed,
+    TimedOut,
+}
+
+fn is_timeout(error: &Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
 }
 
-fn parse_request(reader: &mut BufReader<&TcpStream>) -> Result<Option<Request>> {
+fn parse_request(reader: &mut BufReader<&TcpStream>, stream: &TcpStream) -> Result<ReadOutcome> {
     let mut buffer = String::new();
 
-    reader.read_line(&mut buffer)?;
+    match reader.read_line(&mut buffer) {
+        Ok(0) => return Ok(ReadOutcome::Closed),
+        Ok(_) => {}
+        Err(e) if is_timeout(&e) => {
+            return Ok(if buffer.is_empty() {
+                ReadOutcome::Closed
+            } else {
+                ReadOutcome::TimedOut
+            });
+        }
+        Err(e) => return Err(e),
+    }
+
+    stream.set_read_timeout(None)?;
 
     let parts: Vec<&str> = buffer.split(" ").collect();
     if parts.len() != 3 {
-        return Ok(None);
+        return Ok(ReadOutcome::Closed);
     }
 
     let method = match parts[0] {
@@ -113,7 +284,7 @@ fn parse_request(reader: &mut BufReader<&TcpStream>) -> Result<Option<Request>>
     let path: String = parts[1].into();
     let version: String = parts[2].into();
 
-    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut headers = Headers::new();
 
     loop {
         buffer.clear();
@@ -127,48 +298,189 @@ fn parse_request(reader: &mut BufReader<&TcpStream>) -> Result<Option<Request>>
         let key = &buffer[..index];
         let value = buffer[index + 1..].trim();
 
-        headers.insert(key.into(), value.into());
+        headers.insert(key, value);
     }
 
-    let mut body: Option<Vec<u8>> = None;
-    if method == Method::Post {
-        let content_length = match headers.get("Content-Length") {
-            Some(x) => x.parse::<i32>().unwrap(),
-            None => 0,
-        };
+    let expect_continue = matches!(headers.get("Expect"), Some(value) if value == "100-continue");
 
-        if content_length != 0 {
-            let mut buffer: Vec<u8> = Vec::new();
-            reader
-                .take(content_length as u64)
-                .read_to_end(&mut buffer)?;
-            body = Some(buffer);
-        }
-    }
-
-    Ok(Some(Request {
+    Ok(ReadOutcome::Request(Request {
         method,
         path: path.trim_end().into(),
         version: version.trim_end().into(),
         headers,
-        body,
+        body: None,
+        expect_continue,
     }))
 }
 
+fn accepts_body(request: &Request) -> bool {
+    return request.method == Method::Post
+        && (request.headers.get("Content-Length").is_some()
+            || matches!(request.headers.get("Transfer-Encoding"), Some(value) if value.eq_ignore_ascii_case("chunked")));
+}
+
+fn accepts_upload(request: &Request) -> bool {
+    let path = Path::new(&request.path[7..]);
+
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => metadata(parent).is_ok(),
+        _ => true,
+    }
+}
+
+fn read_body(reader: &mut BufReader<&TcpStream>, request: &Request) -> Result<Option<Vec<u8>>> {
+    let content_length = request.headers.get("Content-Length");
+    let chunked = matches!(request.headers.get("Transfer-Encoding"), Some(value) if value.eq_ignore_ascii_case("chunked"));
+
+    if chunked {
+        if content_length.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Content-Length and Transfer-Encoding: chunked are mutually exclusive",
+            ));
+        }
+
+        return read_chunked_body(reader);
+    }
+
+    let content_length = match content_length {
+        Some(x) => x.parse::<i32>().unwrap(),
+        None => 0,
+    };
+
+    if content_length == 0 {
+        return Ok(None);
+    }
+
+    let mut buffer: Vec<u8> = Vec::new();
+    reader
+        .take(content_length as u64)
+        .read_to_end(&mut buffer)?;
+
+    Ok(Some(buffer))
+}
+
+fn read_chunked_body(reader: &mut BufReader<&TcpStream>) -> Result<Option<Vec<u8>>> {
+    let mut body: Vec<u8> = Vec::new();
+
+    loop {
+        let mut size_line = String::new();
+        reader.read_line(&mut size_line)?;
+
+        let size_str = size_line.trim_end().split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid chunk size"))?;
+
+        if size == 0 {
+            break;
+        }
+
+        let mut chunk = vec![0u8; size];
+        reader.read_exact(&mut chunk)?;
+        body.extend_from_slice(&chunk);
+
+        let mut crlf = [0u8; 2];
+        reader.read_exact(&mut crlf)?;
+    }
+
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+    }
+
+    Ok(Some(body))
+}
+
 fn gzip(buffer: &mut Vec<u8>) -> Vec<u8> {
     let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
     encoder.write_all(buffer).unwrap();
     return encoder.finish().unwrap();
 }
 
+fn deflate(buffer: &mut Vec<u8>) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(buffer).unwrap();
+    return encoder.finish().unwrap();
+}
+
+fn brotli(buffer: &mut Vec<u8>) -> Vec<u8> {
+    let mut output = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut output, 4096, 11, 22);
+        writer.write_all(buffer).unwrap();
+    }
+    return output;
+}
+
+const ENCODERS: [(&str, fn(&mut Vec<u8>) -> Vec<u8>); 3] =
+    [("gzip", gzip), ("deflate", deflate), ("br", brotli)];
+
+fn parse_accept_encoding(value: &str) -> Vec<(String, f32)> {
+    let mut codings: Vec<(String, f32)> = value
+        .split(',')
+        .filter_map(|part| {
+            let part = part.trim();
+            if part.is_empty() {
+                return None;
+            }
+
+            let mut pieces = part.splitn(2, ";q=");
+            let coding = pieces.next()?.trim().to_lowercase();
+            let q = match pieces.next() {
+                Some(q) => q
+                    .trim()
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|q| q.is_finite())
+                    .unwrap_or(1.0),
+                None => 1.0,
+            };
+
+            Some((coding, q))
+        })
+        .collect();
+
+    codings.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+    return codings;
+}
+
 fn encode(request: &Request, response: &mut Response) {
     let mut encoder: Option<(&str, fn(&mut Vec<u8>) -> Vec<u8>)> = None;
+
     if let Some(accept_encoding) = request.headers.get("Accept-Encoding") {
-        for mut name in accept_encoding.split(",") {
-            name = name.trim();
+        let codings = parse_accept_encoding(accept_encoding);
+        let excluded: Vec<&str> = codings
+            .iter()
+            .filter(|(_, q)| *q <= 0.0)
+            .map(|(coding, _)| coding.as_str())
+            .collect();
+
+        for (coding, q) in &codings {
+            if *q <= 0.0 {
+                continue;
+            }
 
-            if "gzip" == name {
-                encoder = Some((name, gzip));
+            let coding = coding.as_str();
+
+            if coding == "identity" {
+                break;
+            }
+
+            if coding == "*" {
+                encoder = ENCODERS
+                    .iter()
+                    .find(|(name, _)| !excluded.contains(name))
+                    .copied();
+                break;
+            }
+
+            if let Some(&found) = ENCODERS.iter().find(|(name, _)| *name == coding) {
+                encoder = Some(found);
+                break;
             }
         }
     }
@@ -178,7 +490,7 @@ fn encode(request: &Request, response: &mut Response) {
             response.body = Some(func(body));
             response
                 .headers
-                .insert("Content-Encoding".into(), name.into());
+                .insert("Content-Encoding", name);
         }
     }
 }
@@ -195,9 +507,9 @@ fn answer(
     if let Some(body) = &response.body {
         response
             .headers
-            .insert("Content-Length".into(), body.len().to_string());
-    } else {
-        response.headers.insert("Content-Length".into(), "0".into());
+            .insert("Content-Length", body.len().to_string());
+    } else if !matches!(response.status, Status::NotModified) {
+        response.headers.insert("Content-Length", "0");
     }
 
     writer.write(request.version.as_bytes())?;
@@ -252,11 +564,85 @@ fn route(request: &Request) -> Response {
         let path = Path::new(&request.path[7..]);
 
         if request.method == Method::Get {
-            return match read(path) {
-                Ok(data) => Response::binary(data),
-                Err(e) if e.kind() == ErrorKind::NotFound => Response::status(Status::NotFound),
-                Err(e) => Response::text(Status::ServerError, format!("{}", e)),
+            let meta = match metadata(path) {
+                Ok(meta) => meta,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    return Response::status(Status::NotFound)
+                }
+                Err(e) => return Response::text(Status::ServerError, format!("{}", e)),
+            };
+
+            let total = meta.len();
+            let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+            let etag = etag(total, modified);
+            let last_modified = httpdate::fmt_http_date(modified);
+
+            if is_not_modified(request, modified, &etag) {
+                let mut response = Response::status(Status::NotModified);
+                response.headers.insert("ETag", etag);
+                response
+                    .headers
+                    .insert("Last-Modified", last_modified);
+                return response;
+            }
+
+            let range = match request.headers.get("Range") {
+                Some(value) => parse_range(value, total),
+                None => Range::Full,
+            };
+
+            if let Range::Unsatisfiable = range {
+                let mut response = Response::status(Status::RangeNotSatisfiable);
+                response
+                    .headers
+                    .insert("Content-Range", format!("bytes */{}", total));
+                return response;
+            }
+
+            let data = match read(path) {
+                Ok(data) => data,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    return Response::status(Status::NotFound)
+                }
+                Err(e) => return Response::text(Status::ServerError, format!("{}", e)),
+            };
+
+            let total = data.len() as u64;
+
+            let mut response = match range {
+                Range::Partial(start, end) if start < total => {
+                    let end = end.min(total - 1);
+                    let slice = data[start as usize..=end as usize].to_vec();
+                    let mut response = Response::with_body(
+                        Status::PartialContent,
+                        "application/octet-stream",
+                        slice,
+                    );
+                    response.headers.insert(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, end, total),
+                    );
+                    response
+                }
+                Range::Partial(..) => {
+                    let mut response = Response::status(Status::RangeNotSatisfiable);
+                    response
+                        .headers
+                        .insert("Content-Range", format!("bytes */{}", total));
+                    return response;
+                }
+                _ => Response::binary(data),
             };
+
+            response
+                .headers
+                .insert("Accept-Ranges", "bytes");
+            response.headers.insert("ETag", etag);
+            response
+                .headers
+                .insert("Last-Modified", last_modified);
+
+            return response;
         } else if request.method == Method::Post {
             let body = request.body.as_ref().unwrap();
             return match write(path, &body) {
@@ -273,7 +659,7 @@ fn route(request: &Request) -> Response {
 fn should_close(request: &Request, response: &mut Response) -> bool {
     return if let Some(value) = request.headers.get("Connection") {
         if value == "close" {
-            response.headers.insert("Connection".into(), "close".into());
+            response.headers.insert("Connection", "close");
             true
         } else {
             false
@@ -283,23 +669,65 @@ fn should_close(request: &Request, response: &mut Response) -> bool {
     };
 }
 
-fn handle(stream: TcpStream) -> Result<()> {
+fn handle(stream: TcpStream, keep_alive_timeout: Duration) -> Result<()> {
     let mut reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
 
     loop {
-        if let Some(request) = parse_request(&mut reader)? {
-            let mut response = route(&request);
-            encode(&request, &mut response);
-
-            let should_close = should_close(&request, &mut response);
-
-            answer(&mut writer, request, response)?;
-
-            if should_close {
+        stream.set_read_timeout(Some(keep_alive_timeout))?;
+
+        let mut request = match parse_request(&mut reader, &stream)? {
+            ReadOutcome::Request(request) => request,
+            ReadOutcome::TimedOut => {
+                let status = Status::RequestTimeout;
+                writer.write(b"HTTP/1.1 ")?;
+                writer.write(status.as_str().as_bytes())?;
+                writer.write(b"\r\nConnection: close\r\n\r\n")?;
+                writer.flush()?;
+                println!("(idle) --> {}", status);
                 break;
             }
+            ReadOutcome::Closed => break,
+        };
+
+        let mut malformed_body = false;
+        let mut rejected_upload = false;
+
+        if accepts_body(&request) {
+            let is_rejected_upload = request.expect_continue
+                && request.path.starts_with("/files/")
+                && !accepts_upload(&request);
+
+            if is_rejected_upload {
+                rejected_upload = true;
+            } else {
+                if request.expect_continue {
+                    writer.write(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                    writer.flush()?;
+                }
+
+                match read_body(&mut reader, &request) {
+                    Ok(body) => request.body = body,
+                    Err(e) if e.kind() == ErrorKind::InvalidData => malformed_body = true,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        let mut response = if rejected_upload {
+            Response::status(Status::NotFound)
+        } else if malformed_body {
+            Response::status(Status::BadRequest)
         } else {
+            route(&request)
+        };
+        encode(&request, &mut response);
+
+        let should_close = malformed_body || should_close(&request, &mut response);
+
+        answer(&mut writer, request, response)?;
+
+        if should_close {
             break;
         }
     }
@@ -317,6 +745,13 @@ fn main() {
         println!("changed directory: {}", path.display());
     }
 
+    let keep_alive_timeout = Duration::from_secs(
+        env::var("KEEP_ALIVE_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_KEEP_ALIVE_TIMEOUT_SECS),
+    );
+
     let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
     println!("listen: 4221");
 
@@ -325,7 +760,7 @@ fn main() {
             Ok(stream) => {
                 println!("accepted new connection");
 
-                thread::spawn(|| match handle(stream) {
+                thread::spawn(move || match handle(stream, keep_alive_timeout) {
                     Ok(_) => {
                         println!("closed connection");
                     }